@@ -2,8 +2,9 @@ use alloc::boxed::Box;
 
 use collections::vec::Vec;
 
+use core::cmp;
 use core::intrinsics::volatile_load;
-use core::{mem, slice};
+use core::{mem, ptr, slice};
 
 use drivers::mmio::Mmio;
 use drivers::pciconfig::PciConfig;
@@ -20,6 +21,25 @@ struct Gtd {
     buffer: u32,
     next: u32,
     end: u32,
+    // Software bookkeeping appended after the hardware-defined words; the
+    // controller never looks past `end`. Set by on_irq once this TD shows
+    // up on the HCCA done queue.
+    done: bool,
+}
+
+// Isochronous TD: covers up to 8 consecutive frames of one stream. Unlike
+// Gtd, each frame gets its own offset/PSW half-word rather than a single
+// buffer/end pair - `offset[i]` is the frame's buffer offset before
+// submission and becomes its transferred size plus condition code once
+// the controller fills it in.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Itd {
+    flags: u32,
+    buffer_page0: u32,
+    next: u32,
+    buffer_end: u32,
+    offset: [u16; 8],
 }
 
 #[repr(packed)]
@@ -31,6 +51,219 @@ struct Ed {
     next: u32,
 }
 
+// Host Controller Communication Area, shared with the controller via DMA.
+// Must live at a 256-byte aligned physical address.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Hcca {
+    interrupt_table: [u32; 32],
+    frame_number: u16,
+    pad: u16,
+    done_head: u32,
+    reserved: [u8; 120],
+}
+
+// A live interrupt IN endpoint: its single TD is automatically re-armed
+// and `callback` invoked with the received bytes each time on_irq finds
+// it on the done queue. `address` is kept around purely so a disconnect
+// can find and tear this endpoint down.
+struct InterruptEp {
+    address: u8,
+    ed: *mut Ed,
+    // The periodic tree placeholder `ed` was linked onto, so a teardown
+    // can splice it back out instead of leaving the schedule pointing at
+    // a freed (and possibly reallocated) descriptor.
+    node: *mut Ed,
+    td: *mut Gtd,
+    buffer: Box<[u8]>,
+    callback: Box<FnMut(&[u8])>,
+}
+
+// A scheduled isochronous stream. `callback` is invoked once with
+// (transferred length, condition code) for every frame in the batch when
+// the last ITD completes; the caller can start another batch from there
+// for continuous streaming.
+struct IsoEp {
+    address: u8,
+    ed: *mut Ed,
+    // Same role as InterruptEp::node - the periodic placeholder `ed`
+    // hangs off of, needed to unlink it on teardown.
+    node: *mut Ed,
+    itds: Vec<*mut Itd>,
+    // Kept alive for the duration of the DMA - the ITDs reference it by
+    // physical address.
+    buffer: Box<[u8]>,
+    callback: Box<FnMut(&[(usize, u32)])>,
+}
+
+fn psw_decode(word: u16) -> (usize, u32) {
+    ((word & 0x7FF) as usize, ((word >> 12) & 0xF) as u32)
+}
+
+// Splice `ed` out of the singly-linked list hanging off the periodic tree
+// placeholder `node`, by walking the chain until something's `next`
+// points at it and bridging over it. Without this, freeing `ed` back to
+// the pool leaves the controller still walking its physical address
+// every frame, and a later allocation can hand that address out to an
+// unrelated, in-flight descriptor.
+unsafe fn unlink_periodic(node: *mut Ed, ed: *mut Ed) {
+    let target = OhciMem::phys(ed as *const u8);
+
+    let mut prev = node;
+    while (*prev).next != 0 {
+        if (*prev).next == target {
+            (*prev).next = (*ed).next;
+            return;
+        }
+        prev = (*prev).next as *mut Ed;
+    }
+}
+
+// A hub discovered below the root ports, and the address (if any) of
+// whatever is attached to each of its downstream ports - so a disconnect
+// can be cascaded to children instead of leaving them stale.
+struct HubDevice {
+    address: u8,
+    children: Vec<Option<u8>>,
+}
+
+const ED_SIZE: usize = 16;
+const ED_ALIGN: usize = 16;
+const TD_SIZE: usize = 32;
+// Itds (also drawn from this pool, see alloc_itd) need 32-byte alignment,
+// stricter than the 16 a plain Gtd would need - since every slot is a
+// TD_SIZE-sized stride from td_off, aligning td_off itself to 32 is
+// enough to align every slot.
+const TD_ALIGN: usize = 32;
+const HCCA_ALIGN: usize = 256;
+
+// 96 EDs covers the 63-node periodic tree plus a healthy number of
+// concurrently open control/interrupt endpoints.
+const POOL_EDS: usize = 96;
+const POOL_TDS: usize = 96;
+
+fn alloc_aligned(len: usize, align: usize) -> (Box<[u8]>, usize) {
+    let mut raw = Vec::with_capacity(len + align);
+    for _ in 0..len + align {
+        raw.push(0u8);
+    }
+
+    let base = raw.as_ptr() as usize;
+    let offset = (align - base % align) % align;
+
+    (raw.into_boxed_slice(), offset)
+}
+
+// Fixed-capacity pool of DMA descriptors for EDs, TDs and the HCCA, handed
+// out at the alignment OHCI requires (16 bytes for EDs, 32 for TDs so
+// that Itds can share the TD pool, 256 for the HCCA) instead of relying on `box`/`Vec` placement. Descriptors are
+// recycled through a free list rather than dropped, so the controller can
+// keep referencing them after the `msg` call that created them returns.
+//
+// Until the kernel exposes uncached or explicitly-flushed mappings this
+// still assumes identity-mapped, cache-coherent memory, same as the rest
+// of this driver - `phys` is the one place that assumption would be
+// replaced with a real virtual-to-physical lookup.
+pub struct OhciMem {
+    ed_pool: Box<[u8]>,
+    ed_off: usize,
+    ed_free: Vec<bool>,
+    td_pool: Box<[u8]>,
+    td_off: usize,
+    td_free: Vec<bool>,
+    hcca_pool: Box<[u8]>,
+    hcca_off: usize,
+}
+
+impl OhciMem {
+    pub fn new() -> Self {
+        let (ed_pool, ed_off) = alloc_aligned(POOL_EDS * ED_SIZE, ED_ALIGN);
+        let (td_pool, td_off) = alloc_aligned(POOL_TDS * TD_SIZE, TD_ALIGN);
+        let (hcca_pool, hcca_off) = alloc_aligned(mem::size_of::<Hcca>(), HCCA_ALIGN);
+
+        let mut ed_free = Vec::with_capacity(POOL_EDS);
+        for _ in 0..POOL_EDS {
+            ed_free.push(true);
+        }
+
+        let mut td_free = Vec::with_capacity(POOL_TDS);
+        for _ in 0..POOL_TDS {
+            td_free.push(true);
+        }
+
+        OhciMem {
+            ed_pool: ed_pool,
+            ed_off: ed_off,
+            ed_free: ed_free,
+            td_pool: td_pool,
+            td_off: td_off,
+            td_free: td_free,
+            hcca_pool: hcca_pool,
+            hcca_off: hcca_off,
+        }
+    }
+
+    pub fn phys(ptr: *const u8) -> u32 {
+        ptr as u32
+    }
+
+    pub unsafe fn hcca(&mut self) -> *mut Hcca {
+        self.hcca_pool.as_mut_ptr().offset(self.hcca_off as isize) as *mut Hcca
+    }
+
+    pub unsafe fn alloc_ed(&mut self) -> *mut Ed {
+        for i in 0..self.ed_free.len() {
+            if self.ed_free[i] {
+                self.ed_free[i] = false;
+                let ptr = self.ed_pool.as_mut_ptr().offset((self.ed_off + i * ED_SIZE) as isize);
+                ptr::write_bytes(ptr, 0, ED_SIZE);
+                return ptr as *mut Ed;
+            }
+        }
+        panic!("OHCI ED pool exhausted");
+    }
+
+    pub unsafe fn free_ed(&mut self, ed: *mut Ed) {
+        let base = self.ed_pool.as_ptr().offset(self.ed_off as isize) as usize;
+        self.ed_free[(ed as usize - base) / ED_SIZE] = true;
+    }
+
+    // Itd and Gtd are the same pool slot size (see TD_SIZE), so general
+    // and isochronous TDs are drawn from, and returned to, the same pool.
+    unsafe fn alloc_td_slot(&mut self) -> *mut u8 {
+        for i in 0..self.td_free.len() {
+            if self.td_free[i] {
+                self.td_free[i] = false;
+                let ptr = self.td_pool.as_mut_ptr().offset((self.td_off + i * TD_SIZE) as isize);
+                ptr::write_bytes(ptr, 0, TD_SIZE);
+                return ptr;
+            }
+        }
+        panic!("OHCI TD pool exhausted");
+    }
+
+    unsafe fn free_td_slot(&mut self, ptr: *mut u8) {
+        let base = self.td_pool.as_ptr().offset(self.td_off as isize) as usize;
+        self.td_free[(ptr as usize - base) / TD_SIZE] = true;
+    }
+
+    pub unsafe fn alloc_td(&mut self) -> *mut Gtd {
+        self.alloc_td_slot() as *mut Gtd
+    }
+
+    pub unsafe fn free_td(&mut self, td: *mut Gtd) {
+        self.free_td_slot(td as *mut u8)
+    }
+
+    pub unsafe fn alloc_itd(&mut self) -> *mut Itd {
+        self.alloc_td_slot() as *mut Itd
+    }
+
+    pub unsafe fn free_itd(&mut self, itd: *mut Itd) {
+        self.free_td_slot(itd as *mut u8)
+    }
+}
+
 const CTRL_CBSR: u32 = 0b11;
 const CTRL_PLE: u32 = 1 << 2;
 const CTRL_IE: u32 = 1 << 3;
@@ -58,6 +291,64 @@ const PORT_STS_PSSC: u32 = 1 << 18;
 const PORT_STS_OCIC: u32 = 1 << 19;
 const PORT_STS_PRSC: u32 = 1 << 20;
 
+// Write-only root hub port command: unlike the other PORT_STS_* bits this
+// has no matching read-side status meaning, it just kicks off a reset.
+const PORT_STS_PRS: u32 = 1 << 4;
+
+const INT_STS_WDH: u32 = 1 << 1;
+const INT_STS_RHSC: u32 = 1 << 6;
+const INT_EN_MIE: u32 = 1 << 31;
+
+// PeriodicStart, in bit times. 0x3E67 is 15975: larger than the default
+// 11999-bit-time frame interval (0x2EDF), not ~90% of it.
+const CTRL_PLE_START: u32 = 0x3E67;
+
+// TD condition code, flags bits 28-31. 0b0000 is "no error", anything else
+// is a transfer failure of some kind (stall, data underrun, CRC, etc).
+const TD_CC_NOERROR: u32 = 0b0000;
+
+// ED flags bit 13: this endpoint is on a low-speed device.
+const ED_SPEED: u32 = 1 << 13;
+
+// ED flags bit 14: the controller follows `next` without touching this
+// ED's TD list. Used for the placeholder nodes of the periodic tree.
+const ED_SKIP: u32 = 1 << 14;
+
+// Binary tree of interrupt EDs backing the HCCA's 32-entry interrupt
+// table: 32 leaf nodes, one per table entry and so each polled every
+// 32ms, merging through 16/8/4/2 nodes polled every 16/8/4/2ms into a
+// single root polled every 1ms. Index ranges per level, flattened into
+// one array of 63 nodes.
+const PERIODIC_LEVELS: [usize; 6] = [32, 16, 8, 4, 2, 1];
+const PERIODIC_LEVEL_OFFSETS: [usize; 6] = [0, 32, 48, 56, 60, 62];
+const PERIODIC_NODES: usize = 63;
+
+// ED flags bit 15: this ED's TD list is made of Itds, not Gtds.
+const ED_ISO: u32 = 1 << 15;
+
+// Ed.head bit 0: the controller sets this when a TD on this ED's list
+// completes with an error, and then stops advancing the list entirely -
+// the queued TD after the failing one is never retired.
+const ED_HEAD_HALTED: u32 = 1 << 0;
+
+// An Itd covers at most 8 frames.
+const ITD_FRAMES: usize = 8;
+
+// USB hub class (bDeviceClass 0x09), layered entirely on the generic
+// control-transfer path every UsbHci implements - no OHCI-specific
+// register access needed to talk to a downstream hub.
+const USB_CLASS_HUB: u8 = 0x09;
+const USB_REQ_GET_DESCRIPTOR: u8 = 0x06;
+const USB_REQ_SET_ADDRESS: u8 = 0x05;
+const HUB_REQ_GET_STATUS: u8 = 0x00;
+const HUB_REQ_SET_FEATURE: u8 = 0x03;
+const HUB_DESC_TYPE: u16 = 0x29 << 8;
+const HUB_FEATURE_PORT_POWER: u16 = 8;
+const HUB_FEATURE_PORT_RESET: u16 = 4;
+// wHubCharacteristics bit 0: 0 = ganged power switching (one port powers
+// them all), 1 = power switched per port.
+const HUB_CHAR_INDIVIDUAL_POWER: u16 = 1 << 0;
+
 #[repr(packed)]
 pub struct OhciRegs {
     pub revision: Mmio<u32>,
@@ -87,16 +378,42 @@ pub struct OhciRegs {
 pub struct Ohci {
     pub regs: &'static mut OhciRegs,
     pub irq: u8,
+    mem: OhciMem,
+    hcca: *mut Hcca,
+    periodic: [*mut Ed; PERIODIC_NODES],
+    periodic_rr: [usize; 6],
+    interrupts: Vec<InterruptEp>,
+    isochronous: Vec<IsoEp>,
+    hubs: Vec<HubDevice>,
+    next_address: u8,
+    // Address assigned to whatever is attached to each root port, if it
+    // was enumerated by handle_root_hub_change after boot. Lets a later
+    // disconnect on that port find what to tear down.
+    root_ports: [Option<u8>; 15],
 }
 
 impl KScheme for Ohci {
     fn on_irq(&mut self, irq: u8) {
         if irq == self.irq {
-            // d("OHCI IRQ\n");
+            let int_sts = self.regs.int_sts.read();
+            if int_sts & INT_STS_WDH == INT_STS_WDH {
+                unsafe { self.reap_done_queue(); }
+
+                self.regs.int_sts.write(INT_STS_WDH);
+            }
+
+            if int_sts & INT_STS_RHSC == INT_STS_RHSC {
+                unsafe { self.handle_root_hub_change(); }
+
+                self.regs.int_sts.write(INT_STS_RHSC);
+            }
         }
     }
 
+    // Some boards are sloppy about actually raising RHSC, so poll the
+    // same per-port change bits in case the interrupt never arrives.
     fn on_poll(&mut self) {
+        unsafe { self.handle_root_hub_change(); }
     }
 }
 
@@ -107,9 +424,21 @@ impl Ohci {
         let base = pci.read(0x10) as usize & 0xFFFFFFF0;
         let regs = &mut *(base as *mut OhciRegs);
 
+        let mut mem = OhciMem::new();
+        let hcca = mem.hcca();
+
         let mut module = box Ohci {
             regs: regs,
             irq: pci.read(0x3C) as u8 & 0xF,
+            mem: mem,
+            hcca: hcca,
+            periodic: [0 as *mut Ed; PERIODIC_NODES],
+            periodic_rr: [0; 6],
+            interrupts: Vec::new(),
+            isochronous: Vec::new(),
+            hubs: Vec::new(),
+            next_address: 1, // address 1 is handed out to the first root port device
+            root_ports: [None; 15],
         };
 
         module.init();
@@ -117,12 +446,474 @@ impl Ohci {
         return module;
     }
 
+    // Walk the (reverse-ordered) HCCA done queue, reporting each TD's
+    // condition code and clearing the queue once consumed.
+    unsafe fn reap_done_queue(&mut self) {
+        let mut td_ptr = (*self.hcca).done_head & 0xFFFFFFF0; // low bits are reserved, mask them
+        (*self.hcca).done_head = 0;
+
+        let mut done = Vec::new();
+        while td_ptr != 0 {
+            let td = &mut *(td_ptr as *mut Gtd);
+            done.push(td as *mut Gtd);
+            td_ptr = td.next;
+        }
+
+        // The done queue links TDs in the reverse order they completed in.
+        for td_ptr in done.iter().rev() {
+            let td = &mut **td_ptr;
+
+            let cc = (td.flags >> 28) & 0xF;
+            if cc != TD_CC_NOERROR {
+                debugln!("OHCI TD error: condition code {:X}", cc);
+            }
+
+            if let Some(i) = self.interrupts.iter().position(|ep| ep.td == *td_ptr) {
+                let buf_ptr = self.interrupts[i].buffer.as_ptr();
+                let buf_len = self.interrupts[i].buffer.len();
+                // CBP is 0 once the whole buffer was filled, otherwise it
+                // points just past the last byte received.
+                let received = if td.buffer == 0 { buf_len } else { (td.buffer - OhciMem::phys(buf_ptr)) as usize };
+                (self.interrupts[i].callback)(slice::from_raw_parts(buf_ptr, received));
+
+                // Re-arm: the controller revisits this ED every time
+                // its branch of the periodic tree comes up again. The ED's
+                // head moved on to its tail when this TD retired, so point
+                // it back at the TD, keeping the toggle carry bit.
+                td.flags = 0b1111 << 28 | 0b10 << 19;
+                td.buffer = OhciMem::phys(buf_ptr);
+                td.next = 0;
+                let ed = self.interrupts[i].ed;
+                (*ed).head = OhciMem::phys(*td_ptr as *const u8) | ((*ed).head & 0b10);
+                continue;
+            }
+
+            let iso_last = self.isochronous.iter().position(|ep| ep.itds.last() == Some(&(*td_ptr as *mut Itd)));
+            if let Some(i) = iso_last {
+                let results = self.report_iso(i);
+                (self.isochronous[i].callback)(&results);
+
+                let ep = self.isochronous.remove(i);
+                unlink_periodic(ep.node, ep.ed);
+                for itd in ep.itds.iter() {
+                    self.mem.free_itd(*itd);
+                }
+                self.mem.free_ed(ep.ed);
+                continue;
+            }
+
+            // Every ITD in a batch raises WDH on its own (DelayInterrupt is
+            // left at 0), not just the last one, so this may be an
+            // in-progress stream's non-final ITD rather than a Gtd. Its
+            // PSW is already sitting in `offset[0]`, read by report_iso
+            // once the last ITD completes - `td` must not be touched as a
+            // Gtd here, since offset 16 in the Itd layout aliases that
+            // PSW, not a `done` flag.
+            let iso_any = self.isochronous.iter().any(|ep| ep.itds.contains(&(*td_ptr as *mut Itd)));
+            if ! iso_any {
+                td.done = true;
+            }
+        }
+    }
+
+    // Read back the per-frame PSW of every ITD in a completed stream.
+    unsafe fn report_iso(&self, i: usize) -> Vec<(usize, u32)> {
+        let mut results = Vec::new();
+
+        for itd in self.isochronous[i].itds.iter() {
+            let frame_count = (((**itd).flags >> 24) & 0b111) as usize + 1;
+            for f in 0..frame_count {
+                results.push(psw_decode((**itd).offset[f]));
+            }
+        }
+
+        results
+    }
+
+    // Build the 63-node periodic tree backing the HCCA's 32-entry
+    // interrupt table and link it into the controller.
+    unsafe fn build_periodic_tree(&mut self) {
+        for i in 0..PERIODIC_NODES {
+            let ed = self.mem.alloc_ed();
+            (*ed).flags = ED_SKIP;
+            self.periodic[i] = ed;
+        }
+
+        for level in 0..PERIODIC_LEVELS.len() - 1 {
+            let offset = PERIODIC_LEVEL_OFFSETS[level];
+            let parent_offset = PERIODIC_LEVEL_OFFSETS[level + 1];
+
+            for i in 0..PERIODIC_LEVELS[level] {
+                let parent = self.periodic[parent_offset + i / 2];
+                (*self.periodic[offset + i]).next = OhciMem::phys(parent as *const u8);
+            }
+        }
+
+        // Table entry i gets the leaf whose index is i with its 5 bits
+        // reversed, so each node of a level is reached at evenly spaced
+        // frames rather than in a run of consecutive ones.
+        for i in 0..PERIODIC_LEVELS[0] {
+            let mut leaf = 0;
+            for bit in 0..5 {
+                if i & (1 << bit) != 0 {
+                    leaf |= 1 << (4 - bit);
+                }
+            }
+            (*self.hcca).interrupt_table[i] = OhciMem::phys(self.periodic[leaf] as *const u8);
+        }
+    }
+
+    // Pick the periodic tree node an endpoint wanting `interval` ms should
+    // hang off of, round-robining within the level to balance frames.
+    fn choose_branch(&mut self, interval: u16) -> usize {
+        let mut level = 0;
+        while level < PERIODIC_LEVELS.len() - 1 && (PERIODIC_LEVELS[0] >> level) > interval as usize {
+            level += 1;
+        }
+
+        let count = PERIODIC_LEVELS[level];
+        let slot = self.periodic_rr[level] % count;
+        self.periodic_rr[level] += 1;
+
+        PERIODIC_LEVEL_OFFSETS[level] + slot
+    }
+
+    // Register a recurring interrupt IN transfer. The TD is re-armed by
+    // on_irq and `callback` invoked with the received bytes every time it
+    // completes.
+    pub unsafe fn interrupt_transfer<F>(&mut self, address: u8, endpoint: u8, low_speed: bool, max_packet: u16, interval: u16, len: usize, callback: F)
+        where F: FnMut(&[u8]) + 'static
+    {
+        let mut buffer = Vec::with_capacity(len);
+        for _ in 0..len {
+            buffer.push(0u8);
+        }
+        let buffer = buffer.into_boxed_slice();
+
+        let td = self.mem.alloc_td();
+        (*td).flags = 0b1111 << 28 | 0b10 << 19;
+        (*td).buffer = OhciMem::phys(buffer.as_ptr());
+        (*td).next = 0;
+        (*td).end = (*td).buffer + len as u32;
+
+        let ed = self.mem.alloc_ed();
+        (*ed).flags = (if low_speed { ED_SPEED } else { 0 }) | (max_packet as u32) << 16 | (endpoint as u32) << 7 | address as u32;
+        (*ed).tail = 0;
+        (*ed).head = OhciMem::phys(td as *const u8);
+
+        let branch = self.choose_branch(interval);
+        let node = self.periodic[branch];
+        (*ed).next = (*node).next;
+        (*node).next = OhciMem::phys(ed as *const u8);
+
+        self.interrupts.push(InterruptEp {
+            address: address,
+            ed: ed,
+            node: node,
+            td: td,
+            buffer: buffer,
+            callback: (box callback) as Box<FnMut(&[u8])>,
+        });
+    }
+
+    // Schedule one batch of an isochronous stream (USB audio/webcam data),
+    // splitting `buffer` across as many 8-frame ITDs as it takes and
+    // linking them onto the periodic tree. `callback` fires once, with a
+    // (transferred length, condition code) pair per frame, when the last
+    // ITD completes; resubmit from there to keep the stream going.
+    pub unsafe fn iso_transfer<F>(&mut self, address: u8, endpoint: u8, max_packet: u16, buffer: Box<[u8]>, callback: F)
+        where F: FnMut(&[(usize, u32)]) + 'static
+    {
+        let frame_size = max_packet as usize;
+        let frame_count = (buffer.len() + frame_size - 1) / frame_size;
+        let start_frame = self.regs.fm_num.read() as u16 + 5; // small scheduling latency margin
+
+        let mut itds = Vec::new();
+        let mut frame = 0;
+        while frame < frame_count {
+            let group = cmp::min(ITD_FRAMES, frame_count - frame);
+            let last_frame = frame + group - 1;
+            let last_frame_len = cmp::min(frame_size, buffer.len() - last_frame * frame_size);
+
+            let group_start = OhciMem::phys(buffer.as_ptr().offset((frame * frame_size) as isize));
+            let page0 = group_start & 0xFFFFF000;
+
+            let itd = self.mem.alloc_itd();
+            (*itd).flags = 0b1111 << 28
+                | ((group - 1) as u32) << 24
+                | (start_frame.wrapping_add(frame as u16) as u32);
+            // BufferPage0 only carries the page number (bits 31:12); a
+            // frame's address comes from the matching offset/PSW entry,
+            // whose own bit 12 picks BufferPage0's page or BufferEnd's
+            // page, per the OHCI ITD two-page addressing model.
+            (*itd).buffer_page0 = page0;
+            // BufferEnd describes only this ITD's own up-to-8 frames, not
+            // the whole (possibly much longer) stream buffer.
+            (*itd).buffer_end = OhciMem::phys(buffer.as_ptr().offset((last_frame * frame_size + last_frame_len - 1) as isize));
+
+            for i in 0..group {
+                let frame_addr = OhciMem::phys(buffer.as_ptr().offset(((frame + i) * frame_size) as isize));
+                if frame_addr & 0xFFFFF000 == page0 {
+                    (*itd).offset[i] = (frame_addr & 0xFFF) as u16;
+                } else {
+                    (*itd).offset[i] = 0x1000 | (frame_addr & 0xFFF) as u16;
+                }
+            }
+
+            if let Some(prev) = itds.last() {
+                (*(*prev as *mut Itd)).next = OhciMem::phys(itd as *const u8);
+            }
+            itds.push(itd);
+
+            frame += group;
+        }
+
+        let ed = self.mem.alloc_ed();
+        (*ed).flags = ED_ISO | (max_packet as u32) << 16 | (endpoint as u32) << 7 | address as u32;
+        (*ed).tail = 0;
+        (*ed).head = OhciMem::phys(itds[0] as *const u8);
+
+        let branch = self.choose_branch(1);
+        let node = self.periodic[branch];
+        (*ed).next = (*node).next;
+        (*node).next = OhciMem::phys(ed as *const u8);
+
+        self.isochronous.push(IsoEp {
+            address: address,
+            ed: ed,
+            node: node,
+            itds: itds,
+            buffer: buffer,
+            callback: (box callback) as Box<FnMut(&[(usize, u32)])>,
+        });
+    }
+
+    // fm_num advances once per millisecond - use it as a coarse clock
+    // rather than a fixed-iteration spin.
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            let start = self.regs.fm_num.read();
+            while self.regs.fm_num.read() == start {}
+        }
+    }
+
+    // Assign an address to whatever is on the default pipe, fetch its
+    // device descriptor, and recurse into hub_attach if it turns out to
+    // be a hub. This is the same enumeration `init` runs for root ports,
+    // just reachable for devices that only exist behind a hub.
+    unsafe fn enumerate_device(&mut self, low_speed: bool) {
+        let address = self.next_address;
+        self.next_address += 1;
+
+        let set_address = Setup {
+            request_type: 0x00,
+            request: USB_REQ_SET_ADDRESS,
+            value: address as u16,
+            index: 0,
+            length: 0
+        };
+        self.msg(0, 0, &[UsbMsg::Setup(&set_address)]);
+
+        self.delay_ms(2);
+
+        let mut desc = [0u8; 18];
+        let get_desc = Setup {
+            request_type: 0x80,
+            request: USB_REQ_GET_DESCRIPTOR,
+            value: 0x0100, // DEVICE descriptor, index 0
+            index: 0,
+            length: desc.len() as u16
+        };
+        self.msg(address, 0, &[UsbMsg::Setup(&get_desc), UsbMsg::In(&mut desc)]);
+
+        let class = desc[4];
+        debugln!("USB device {:X}: class {:X}{}", address, class, if low_speed { " (low speed)" } else { "" });
+
+        if class == USB_CLASS_HUB {
+            self.hub_attach(address);
+        }
+    }
+
+    // Power up every downstream port of a newly-enumerated hub and probe
+    // each one for an already-connected device.
+    unsafe fn hub_attach(&mut self, address: u8) {
+        let mut hub_desc = [0u8; 8];
+        let get_hub_desc = Setup {
+            request_type: 0xA0, // device-to-host, class, device
+            request: USB_REQ_GET_DESCRIPTOR,
+            value: HUB_DESC_TYPE,
+            index: 0,
+            length: hub_desc.len() as u16
+        };
+        self.msg(address, 0, &[UsbMsg::Setup(&get_hub_desc), UsbMsg::In(&mut hub_desc)]);
+
+        let num_ports = hub_desc[2] as usize;
+        let characteristics = (hub_desc[3] as u16) | (hub_desc[4] as u16) << 8;
+        let individual_power = characteristics & HUB_CHAR_INDIVIDUAL_POWER != 0;
+        let pwr_on_to_good_ms = hub_desc[5] as u32 * 2;
+
+        let mut hub = HubDevice {
+            address: address,
+            children: Vec::with_capacity(num_ports)
+        };
+
+        for port in 1..num_ports + 1 {
+            hub.children.push(None);
+
+            // Gang-powered hubs turn every port on from a single request;
+            // only individually-switched ones need one per port.
+            if individual_power || port == 1 {
+                let set_power = Setup {
+                    request_type: 0x23, // host-to-device, class, other (port)
+                    request: HUB_REQ_SET_FEATURE,
+                    value: HUB_FEATURE_PORT_POWER,
+                    index: port as u16,
+                    length: 0
+                };
+                self.msg(address, 0, &[UsbMsg::Setup(&set_power)]);
+            }
+        }
+
+        self.delay_ms(pwr_on_to_good_ms);
+
+        self.hubs.push(hub);
+
+        for port in 1..num_ports + 1 {
+            self.hub_probe_port(address, port as u16);
+        }
+    }
+
+    unsafe fn hub_port_status(&mut self, address: u8, port: u16) -> u32 {
+        let mut status = [0u8; 4];
+        let get_status = Setup {
+            request_type: 0xA3, // device-to-host, class, other (port)
+            request: HUB_REQ_GET_STATUS,
+            value: 0,
+            index: port,
+            length: status.len() as u16
+        };
+        self.msg(address, 0, &[UsbMsg::Setup(&get_status), UsbMsg::In(&mut status)]);
+
+        (status[0] as u32) | (status[1] as u32) << 8 | (status[2] as u32) << 16 | (status[3] as u32) << 24
+    }
+
+    // On a connected port: reset it, read back its speed, and run the
+    // same enumeration a root port gets.
+    unsafe fn hub_probe_port(&mut self, address: u8, port: u16) {
+        if self.hub_port_status(address, port) & PORT_STS_CCS == 0 {
+            return;
+        }
+
+        let set_reset = Setup {
+            request_type: 0x23,
+            request: HUB_REQ_SET_FEATURE,
+            value: HUB_FEATURE_PORT_RESET,
+            index: port,
+            length: 0
+        };
+        self.msg(address, 0, &[UsbMsg::Setup(&set_reset)]);
+
+        self.delay_ms(50);
+
+        let port_sts = self.hub_port_status(address, port);
+        if port_sts & PORT_STS_CCS == 0 {
+            return;
+        }
+
+        let low_speed = port_sts & PORT_STS_LSDA == PORT_STS_LSDA;
+        let child_address = self.next_address;
+
+        self.enumerate_device(low_speed);
+
+        if let Some(hub) = self.hubs.iter_mut().find(|h| h.address == address) {
+            hub.children[port as usize - 1] = Some(child_address);
+        }
+    }
+
+    // Release everything this driver owns for `address`: its interrupt and
+    // isochronous endpoints, and - if it was a hub - every child hanging
+    // off of it, recursively. No device scheme is notified, and the address
+    // itself is not handed out again: state kept elsewhere for this device
+    // (such as open files) is not released here.
+    unsafe fn teardown_address(&mut self, address: u8) {
+        while let Some(i) = self.interrupts.iter().position(|ep| ep.address == address) {
+            let ep = self.interrupts.remove(i);
+            unlink_periodic(ep.node, ep.ed);
+            self.mem.free_td(ep.td);
+            self.mem.free_ed(ep.ed);
+        }
+
+        while let Some(i) = self.isochronous.iter().position(|ep| ep.address == address) {
+            let ep = self.isochronous.remove(i);
+            unlink_periodic(ep.node, ep.ed);
+            for itd in ep.itds.iter() {
+                self.mem.free_itd(*itd);
+            }
+            self.mem.free_ed(ep.ed);
+        }
+
+        if let Some(i) = self.hubs.iter().position(|h| h.address == address) {
+            let hub = self.hubs.remove(i);
+            for child in hub.children.iter() {
+                if let Some(child_address) = *child {
+                    self.teardown_address(child_address);
+                }
+            }
+        }
+
+        debugln!("USB device {:X} disconnected", address);
+    }
+
+    // React to a RootHubStatusChange interrupt (or a poll, for controllers
+    // that don't raise it reliably): look for ports whose connect status
+    // changed, and bring the new device up or tear the old one down.
+    unsafe fn handle_root_hub_change(&mut self) {
+        let ndp = self.regs.rh_desc_a.read() & 0xF;
+        for i in 0..ndp as usize {
+            let port_sts = self.regs.port_sts[i].read();
+
+            if port_sts & PORT_STS_CSC == PORT_STS_CSC {
+                self.regs.port_sts[i].write(PORT_STS_CSC); // acknowledge
+
+                if port_sts & PORT_STS_CCS == PORT_STS_CCS {
+                    self.delay_ms(100); // debounce
+
+                    self.regs.port_sts[i].write(PORT_STS_PRS);
+                    while ! self.regs.port_sts[i].readf(PORT_STS_PRSC) {}
+                    self.regs.port_sts[i].write(PORT_STS_PRSC);
+
+                    while ! self.regs.port_sts[i].readf(PORT_STS_PES) {
+                        self.regs.port_sts[i].writef(PORT_STS_PES, true);
+                    }
+
+                    let low_speed = self.regs.port_sts[i].readf(PORT_STS_LSDA);
+                    let address = self.next_address;
+                    self.enumerate_device(low_speed);
+                    self.root_ports[i] = Some(address);
+                } else if let Some(address) = self.root_ports[i].take() {
+                    self.teardown_address(address);
+                }
+            }
+
+            if port_sts & PORT_STS_PRSC == PORT_STS_PRSC {
+                self.regs.port_sts[i].write(PORT_STS_PRSC);
+            }
+        }
+    }
+
     pub unsafe fn init(&mut self) {
         debugln!("OHCI on: {:X}, IRQ: {:X}", (self.regs as *mut OhciRegs) as usize, self.irq);
 
         let ctrl = self.regs.control.read();
         self.regs.control.write(ctrl & (0xFFFFFFFF - CTRL_HCFS) | 0b10 << 6);
 
+        self.regs.hcca.write(OhciMem::phys(self.hcca as *const u8));
+        self.regs.int_en.write(INT_STS_WDH | INT_STS_RHSC | INT_EN_MIE);
+
+        self.build_periodic_tree();
+        self.regs.periodic_start.write(CTRL_PLE_START);
+        self.regs.control.write(self.regs.control.read() | CTRL_PLE);
+
         let ndp = self.regs.rh_desc_a.read() & 0xF;
         for i in 0..ndp as usize {
             debugln!("Port {}: {:X}", i, self.regs.port_sts[i].read());
@@ -135,7 +926,14 @@ impl Ohci {
                     self.regs.port_sts[i].writef(PORT_STS_PES, true);
                 }
 
-                self.device(i as u8);
+                // Same enumeration handle_root_hub_change runs for a
+                // hotplugged device - a hub already attached at boot needs
+                // its descriptor fetched and its downstream ports probed
+                // exactly as much as one that shows up afterwards.
+                let low_speed = self.regs.port_sts[i].readf(PORT_STS_LSDA);
+                let address = self.next_address;
+                self.enumerate_device(low_speed);
+                self.root_ports[i] = Some(address);
             }
         }
     }
@@ -144,64 +942,70 @@ impl Ohci {
 
 impl UsbHci for Ohci {
     fn msg(&mut self, address: u8, endpoint: u8, msgs: &[UsbMsg]) -> usize {
-        let mut tds = Vec::new();
+        let mut tds: Vec<*mut Gtd> = Vec::new();
+        // The controller rewrites a TD's `buffer` field (CBP) in place as
+        // it transfers data, typically down to 0 on full success - so the
+        // original start address has to be saved separately to recover
+        // the transferred length afterwards.
+        let mut starts: Vec<u32> = Vec::new();
         for msg in msgs.iter().rev() {
             let link_ptr = match tds.last() {
-                Some(td) => (td as *const Gtd) as u32,
+                Some(td) => OhciMem::phys(*td as *const u8),
                 None => 0
             };
 
-            match *msg {
-                UsbMsg::Setup(setup) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b00 << 19,
-                    buffer: (setup as *const Setup) as u32,
-                    next: link_ptr,
-                    end: (setup as *const Setup) as u32 + mem::size_of::<Setup>() as u32
-                }),
-                UsbMsg::In(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b10 << 19,
-                    buffer: data.as_ptr() as u32,
-                    next: link_ptr,
-                    end: data.as_ptr() as u32 + data.len() as u32
-                }),
-                UsbMsg::InIso(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b10 << 19,
-                    buffer: data.as_ptr() as u32,
-                    next: link_ptr,
-                    end: data.as_ptr() as u32 + data.len() as u32
-                }),
-                UsbMsg::Out(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b01 << 19,
-                    buffer: data.as_ptr() as u32,
-                    next: link_ptr,
-                    end: data.as_ptr() as u32 + data.len() as u32
-                }),
-                UsbMsg::OutIso(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b01 << 19,
-                    buffer: data.as_ptr() as u32,
-                    next: link_ptr,
-                    end: data.as_ptr() as u32 + data.len() as u32
-                })
+            let td = unsafe { self.mem.alloc_td() };
+            unsafe {
+                match *msg {
+                    UsbMsg::Setup(setup) => {
+                        (*td).flags = 0b1111 << 28 | 0b00 << 19;
+                        (*td).buffer = OhciMem::phys(setup as *const Setup as *const u8);
+                        (*td).next = link_ptr;
+                        (*td).end = (*td).buffer + mem::size_of::<Setup>() as u32;
+                    },
+                    UsbMsg::In(ref data) => {
+                        (*td).flags = 0b1111 << 28 | 0b10 << 19;
+                        (*td).buffer = OhciMem::phys(data.as_ptr());
+                        (*td).next = link_ptr;
+                        (*td).end = (*td).buffer + data.len() as u32;
+                    },
+                    UsbMsg::InIso(ref data) => {
+                        (*td).flags = 0b1111 << 28 | 0b10 << 19;
+                        (*td).buffer = OhciMem::phys(data.as_ptr());
+                        (*td).next = link_ptr;
+                        (*td).end = (*td).buffer + data.len() as u32;
+                    },
+                    UsbMsg::Out(ref data) => {
+                        (*td).flags = 0b1111 << 28 | 0b01 << 19;
+                        (*td).buffer = OhciMem::phys(data.as_ptr());
+                        (*td).next = link_ptr;
+                        (*td).end = (*td).buffer + data.len() as u32;
+                    },
+                    UsbMsg::OutIso(ref data) => {
+                        (*td).flags = 0b1111 << 28 | 0b01 << 19;
+                        (*td).buffer = OhciMem::phys(data.as_ptr());
+                        (*td).next = link_ptr;
+                        (*td).end = (*td).buffer + data.len() as u32;
+                    }
+                }
             }
+
+            starts.push(unsafe { (*td).buffer });
+            tds.push(td);
         }
 
         let mut count = 0;
 
         if ! tds.is_empty() {
-            let ed = box Ed {
-                flags: 1024 << 16 | (endpoint as u32) << 7 | address as u32,
-                tail: 0,
-                head: (tds.last().unwrap() as *const Gtd) as u32,
-                next: 0
-            };
-
-            //TODO: Calculate actual bytes
-            for td in tds.iter().rev() {
-                count += (td.end - td.buffer) as usize;
+            let ed = unsafe { self.mem.alloc_ed() };
+            unsafe {
+                (*ed).flags = 1024 << 16 | (endpoint as u32) << 7 | address as u32;
+                (*ed).tail = 0;
+                (*ed).head = OhciMem::phys(*tds.last().unwrap() as *const u8);
+                (*ed).next = 0;
             }
 
-            /*
-            self.regs.control_head.write((&*ed as *const Ed) as u32);
+            self.regs.control_head.write(OhciMem::phys(ed as *const u8));
             while ! self.regs.control.readf(CTRL_CLE) {
                 self.regs.control.writef(CTRL_CLE, true);
             }
@@ -209,10 +1013,23 @@ impl UsbHci for Ohci {
                 self.regs.cmd_sts.writef(CMD_STS_CLF, true);
             }
 
-            for td in tds.iter().rev() {
-                while unsafe { volatile_load(td as *const Gtd).flags } & 0b1111 << 28 == 0b1111 << 28 {
-                    //unsafe { context_switch(false) };
+            // tds[0] is the last TD the controller visits (its `next` is 0),
+            // so its completion flag marks the whole chain done - unless an
+            // earlier TD in the chain errors first, in which case the ED
+            // halts and tds[0] is never reached, so also watch for that.
+            let mut halted = false;
+            loop {
+                if unsafe { volatile_load(&(*tds[0]).done as *const bool) } {
+                    break;
                 }
+                if unsafe { volatile_load(&(*ed).head as *const u32) } & ED_HEAD_HALTED != 0 {
+                    halted = true;
+                    break;
+                }
+            }
+
+            if halted {
+                debugln!("OHCI msg: ED halted, aborting wait for address {}, endpoint {}", address, endpoint);
             }
 
             while self.regs.cmd_sts.readf(CMD_STS_CLF) {
@@ -222,7 +1039,20 @@ impl UsbHci for Ohci {
                 self.regs.control.writef(CTRL_CLE, false);
             }
             self.regs.control_head.write(0);
-            */
+
+            unsafe {
+                for (i, td) in tds.iter().enumerate() {
+                    // `buffer` (CBP) is 0 once the HC has consumed the
+                    // whole TD, otherwise it points at the next byte the
+                    // HC would have transferred - so the remaining byte
+                    // count, not `buffer` itself, is what subtracts from
+                    // the TD's original length.
+                    let remaining = if (**td).buffer == 0 { 0 } else { (**td).end - (**td).buffer };
+                    count += ((**td).end - starts[i] - remaining) as usize;
+                    self.mem.free_td(*td);
+                }
+                self.mem.free_ed(ed);
+            }
         }
 
         count